@@ -1,6 +1,9 @@
-use std::time::Duration;
+use std::{collections::HashSet, time::Duration};
 
-use spotify::SpotifyHandler;
+use chrono::Utc;
+use rand::Rng;
+use regex::Regex;
+use spotify::{SpotifyError, SpotifyHandler, Track};
 use tokio::{task, time::sleep};
 use translate::TranslationHandler;
 use twitch_irc::{login::StaticLoginCredentials, TCPTransport, TwitchIRCClient};
@@ -8,7 +11,9 @@ use weather::WeatherHandler;
 
 use crate::{
     command_handler::CommandHandlerError,
-    db::{DBConn, DBConnError},
+    db::{DBConn, DBConnError, Quote},
+    history::MessageHistory,
+    metrics::Metrics,
     twitch_api::TwitchApi,
 };
 
@@ -36,15 +41,23 @@ pub struct ActionHandler {
     weather_handler: WeatherHandler,
     spotify_handler: SpotifyHandler,
     translator: TranslationHandler,
+    history: MessageHistory,
+    metrics: Metrics,
 }
 
 impl ActionHandler {
-    pub fn new(db_conn: DBConn, twitch_api: TwitchApi) -> Self {
+    pub fn new(
+        db_conn: DBConn,
+        twitch_api: TwitchApi,
+        history: MessageHistory,
+        metrics: Metrics,
+    ) -> Self {
         let weather_handler = WeatherHandler::new(db_conn.get_openweathermap_api_key().unwrap());
         let translator = TranslationHandler::new();
         let spotify_handler = SpotifyHandler::new(
             db_conn.get_spotify_cilent_id().unwrap(),
             db_conn.get_spotify_client_secret().unwrap(),
+            db_conn.clone(),
         );
 
         Self {
@@ -53,21 +66,67 @@ impl ActionHandler {
             weather_handler,
             translator,
             spotify_handler,
+            history,
+            metrics,
         }
     }
 
+    ///Applies a `s/pattern/replacement/flags` rewrite to the most recent matching
+    ///message in the channel's history, returning the text to post back in chat.
+    pub fn sed(&self, channel: &str, input: &str) -> Option<String> {
+        let (pattern, replacement, flags) = parse_sed(input)?;
+
+        let pattern = match flags.contains('i') {
+            true => format!("(?i){}", pattern),
+            false => pattern,
+        };
+
+        let regex = match Regex::new(&pattern) {
+            Ok(regex) => regex,
+            Err(_) => return Some(String::from("invalid regex")),
+        };
+
+        for (user, text) in self.history.recent(channel).iter().rev() {
+            if regex.is_match(text) {
+                let rewritten = match flags.contains('g') {
+                    true => regex.replace_all(text, replacement.as_str()),
+                    false => regex.replace(text, replacement.as_str()),
+                };
+
+                return Some(format!("<{}> meant: {}", user, rewritten));
+            }
+        }
+
+        Some(String::from("no matching message found"))
+    }
+
     pub async fn run(
         &self,
         action: &str,
         args: &Vec<String>,
         channel: &str,
+        user: &str,
         client: &TwitchIRCClient<TCPTransport, StaticLoginCredentials>,
     ) -> Result<Option<String>, CommandHandlerError> {
         println!("Executing action {} with arguments {:?}", action, args);
 
+        self.metrics.incr_command(action);
+
         match action {
             "spotify" => Ok(Some(self.get_spotify(channel).await?)),
+            "eval" => Ok(Some(self.eval(channel, user, &args.join(" "))?)),
+            "grab" => match args.first() {
+                Some(target) => Ok(Some(self.grab(channel, user, target)?)),
+                None => Ok(Some(String::from("usage: grab <username>"))),
+            },
+            "quote" => Ok(Some(self.quote(channel, args)?)),
+            "remind" => Ok(Some(self.remind(channel, user, args)?)),
+            "owo" => Ok(Some(owoify(&args.join(" ")))),
+            "mock" => Ok(Some(mock(&args.join(" ")))),
+            "leet" => Ok(Some(leet(&args.join(" ")))),
             "lastsong" => Ok(Some(self.get_spotify_last_song(channel).await?)),
+            "commonsongs" => Ok(Some(self.common_songs(args).await?)),
+            "spotifylink" => Ok(self.resolve_spotify_link(&args.join(" ")).await),
             "hitman" => Ok(Some(
                 self.hitman(channel, args.first().unwrap(), client).await?,
             )),
@@ -101,45 +160,200 @@ impl ActionHandler {
         }
     }
 
-    async fn get_spotify(&self, channel: &str) -> Result<String, CommandHandlerError> {
-        match self.db_conn.get_spotify_access_token(channel) {
-            Ok((access_token, _)) => {
-                match self.spotify_handler.get_current_song(&access_token).await? {
-                    Some(song) => Ok(song),
-                    None => Ok(String::from("no song is currently playing")),
-                }
+    fn eval(&self, channel: &str, user: &str, expr: &str) -> Result<String, CommandHandlerError> {
+        let previous = self.db_conn.get_eval_result(channel, user)?;
+
+        let mut ctx = meval::Context::new();
+        ctx.var("x", previous);
+
+        match meval::eval_str_with_context(expr, &ctx) {
+            Ok(result) => {
+                self.db_conn.set_eval_result(channel, user, &result)?;
+                Ok(format!("{}", result))
             }
-            Err(e) => match e {
-                DBConnError::NotFound => Ok(String::from("not configured for this channel")),
-                _ => Err(CommandHandlerError::DBError(e)),
-            },
+            Err(e) => Ok(format!("error: {}", e)),
         }
     }
 
-    async fn get_spotify_last_song(&self, channel: &str) -> Result<String, CommandHandlerError> {
-        match self.db_conn.get_spotify_access_token(channel) {
-            Ok((access_token, _)) => {
-                match self
-                    .spotify_handler
-                    .get_recently_played(&access_token)
-                    .await
-                {
-                    Ok(recently_played) => {
-                        let last_track = &recently_played.items.first().unwrap().track;
-
-                        Ok(format!(
-                            "{} - {}",
-                            last_track.artists.first().unwrap().name,
-                            last_track.name
-                        ))
+    fn grab(
+        &self,
+        channel: &str,
+        grabbed_by: &str,
+        target: &str,
+    ) -> Result<String, CommandHandlerError> {
+        let target = target.to_lowercase();
+
+        match self
+            .history
+            .recent(channel)
+            .iter()
+            .rev()
+            .find(|(user, _)| user.to_lowercase() == target)
+        {
+            Some((author, text)) => {
+                self.db_conn.add_quote(channel, author, text, grabbed_by)?;
+                Ok(format!("quoted {}: {}", author, text))
+            }
+            None => Ok(format!("no recent message from {} found", target)),
+        }
+    }
+
+    fn quote(&self, channel: &str, args: &[String]) -> Result<String, CommandHandlerError> {
+        match args.first() {
+            None => Ok(match self.db_conn.get_random_quote(channel)? {
+                Some(quote) => format_quote(&quote),
+                None => String::from("no quotes found"),
+            }),
+            Some(arg) => match arg.parse::<u64>() {
+                Ok(id) => Ok(match self.db_conn.get_quote_by_id(channel, id)? {
+                    Some(quote) => format_quote(&quote),
+                    None => format!("no quote with id {}", id),
+                }),
+                Err(_) => {
+                    let matches = self.db_conn.search_quotes(channel, &args.join(" "))?;
+
+                    match matches.first() {
+                        Some(quote) => match matches.len() - 1 {
+                            0 => Ok(format_quote(quote)),
+                            remaining => Ok(format!("{} (+{} more)", format_quote(quote), remaining)),
+                        },
+                        None => Ok(String::from("no matching quotes found")),
                     }
-                    Err(e) => Ok(format!("error getting last song: {:?}", e)),
                 }
+            },
+        }
+    }
+
+    fn remind(
+        &self,
+        channel: &str,
+        user: &str,
+        args: &[String],
+    ) -> Result<String, CommandHandlerError> {
+        let duration = match args.first().and_then(|token| parse_duration(token)) {
+            Some(duration) => duration,
+            None => return Ok(String::from("invalid duration")),
+        };
+
+        let message = args[1..].join(" ");
+        let remind_at = Utc::now() + duration;
+
+        self.db_conn
+            .add_reminder(channel, user, user, &message, &remind_at)?;
+
+        Ok(format!("reminder set for {}", user))
+    }
+
+    async fn get_spotify(&self, channel: &str) -> Result<String, CommandHandlerError> {
+        self.metrics.incr_api_call("spotify");
+
+        match self.spotify_handler.get_current_song(channel).await {
+            Ok(Some(song)) => Ok(song),
+            Ok(None) => Ok(String::from("no song is currently playing")),
+            Err(SpotifyError::Db(DBConnError::NotFound)) => {
+                Ok(String::from("not configured for this channel"))
             }
-            Err(e) => match e {
-                DBConnError::NotFound => Ok(String::from("not configured for this channel")),
-                _ => Err(CommandHandlerError::DBError(e)),
+            Err(e) => {
+                self.metrics.incr_spotify_error();
+                Ok(format!("error getting current song: {:?}", e))
+            }
+        }
+    }
+
+    async fn get_spotify_last_song(&self, channel: &str) -> Result<String, CommandHandlerError> {
+        self.metrics.incr_api_call("spotify");
+
+        match self.spotify_handler.get_recently_played(channel, 1).await {
+            Ok(tracks) => match tracks.first() {
+                Some(track) => Ok(format!(
+                    "{} - {}",
+                    track
+                        .artists
+                        .first()
+                        .map(|artist| artist.name.as_str())
+                        .unwrap_or_default(),
+                    track.name
+                )),
+                None => Ok(String::from("no recently played songs")),
             },
+            Err(SpotifyError::Db(DBConnError::NotFound)) => {
+                Ok(String::from("not configured for this channel"))
+            }
+            Err(e) => {
+                self.metrics.incr_spotify_error();
+                Ok(format!("error getting last song: {:?}", e))
+            }
+        }
+    }
+
+    pub async fn resolve_spotify_link(&self, text: &str) -> Option<String> {
+        self.metrics.incr_api_call("spotify");
+        self.spotify_handler.resolve_link(text).await
+    }
+
+    async fn common_songs(&self, args: &[String]) -> Result<String, CommandHandlerError> {
+        let (first, second) = match (args.first(), args.get(1)) {
+            (Some(first), Some(second)) => (first, second),
+            _ => return Ok(String::from("two channels must be specified")),
+        };
+
+        let first_tracks = match self.recently_played_tracks(first).await? {
+            Some(tracks) => tracks,
+            None => return Ok(format!("{} is not configured", first)),
+        };
+        let second_tracks = match self.recently_played_tracks(second).await? {
+            Some(tracks) => tracks,
+            None => return Ok(format!("{} is not configured", second)),
+        };
+
+        let first_ids: HashSet<String> = first_tracks.iter().map(|track| track.id.clone()).collect();
+        let second_ids: HashSet<String> =
+            second_tracks.iter().map(|track| track.id.clone()).collect();
+        let shared: HashSet<String> = first_ids.intersection(&second_ids).cloned().collect();
+
+        if shared.is_empty() {
+            return Ok(format!("{} and {} have no songs in common", first, second));
+        }
+
+        let mut seen = HashSet::new();
+        let displays: Vec<String> = first_tracks
+            .iter()
+            .filter(|track| shared.contains(&track.id) && seen.insert(track.id.clone()))
+            .map(|track| {
+                format!(
+                    "{} - {}",
+                    track
+                        .artists
+                        .first()
+                        .map(|artist| artist.name.as_str())
+                        .unwrap_or_default(),
+                    track.name
+                )
+            })
+            .collect();
+
+        const LIMIT: usize = 5;
+        let shown = displays.iter().take(LIMIT).cloned().collect::<Vec<_>>().join(", ");
+
+        Ok(match displays.len() > LIMIT {
+            true => format!("{} and {} more", shown, displays.len() - LIMIT),
+            false => shown,
+        })
+    }
+
+    async fn recently_played_tracks(
+        &self,
+        channel: &str,
+    ) -> Result<Option<Vec<Track>>, CommandHandlerError> {
+        self.metrics.incr_api_call("spotify");
+
+        match self.spotify_handler.get_recently_played(channel, 50).await {
+            Ok(tracks) => Ok(Some(tracks)),
+            Err(SpotifyError::Db(DBConnError::NotFound)) => Ok(None),
+            Err(_) => {
+                self.metrics.incr_spotify_error();
+                Ok(Some(Vec::new()))
+            }
         }
     }
 
@@ -230,6 +444,8 @@ impl ActionHandler {
     }
 
     async fn get_weather(&self, location: &str) -> Result<String, CommandHandlerError> {
+        self.metrics.incr_api_call("weather");
+
         match self.weather_handler.get_weather(location.to_owned()).await {
             Ok(weather) => Ok(format!(
                 "{}, {}: {}°C, {}",
@@ -246,6 +462,8 @@ impl ActionHandler {
     }
 
     async fn translate(&self, text: &str) -> Result<String, CommandHandlerError> {
+        self.metrics.incr_api_call("translate");
+
         match self.translator.translate(text).await {
             Ok(translation) => Ok(format!(
                 "{} -> {}: {}",
@@ -255,3 +473,140 @@ impl ActionHandler {
         }
     }
 }
+
+///Parses a compound human duration such as `1h30m`, `45s` or `2d` into a
+///`chrono::Duration`, returning `None` on malformed input.
+fn parse_duration(input: &str) -> Option<chrono::Duration> {
+    let mut total = chrono::Duration::zero();
+    let mut number = String::new();
+
+    for ch in input.chars() {
+        if ch.is_ascii_digit() {
+            number.push(ch);
+        } else {
+            if number.is_empty() {
+                return None;
+            }
+            let value: i64 = number.parse().ok()?;
+            total = total
+                + match ch {
+                    's' => chrono::Duration::seconds(value),
+                    'm' => chrono::Duration::minutes(value),
+                    'h' => chrono::Duration::hours(value),
+                    'd' => chrono::Duration::days(value),
+                    _ => return None,
+                };
+            number.clear();
+        }
+    }
+
+    match number.is_empty() {
+        true => Some(total),
+        false => None,
+    }
+}
+
+fn format_quote(quote: &Quote) -> String {
+    format!("#{} {}: {}", quote.id, quote.author, quote.text)
+}
+
+///Maximum length of a Twitch chat message.
+const MAX_MESSAGE_LENGTH: usize = 500;
+
+fn truncate(input: String) -> String {
+    match input.chars().count() > MAX_MESSAGE_LENGTH {
+        true => input.chars().take(MAX_MESSAGE_LENGTH).collect(),
+        false => input,
+    }
+}
+
+fn owoify(input: &str) -> String {
+    const SUFFIXES: [&str; 4] = ["~", " OwO", " UwU", " >w<"];
+
+    let chars: Vec<char> = input.chars().collect();
+    let mut out = String::new();
+
+    for (i, ch) in chars.iter().enumerate() {
+        match ch {
+            'r' | 'l' => out.push('w'),
+            'R' | 'L' => out.push('W'),
+            'n' | 'N' => {
+                out.push(*ch);
+                if chars
+                    .get(i + 1)
+                    .map(|next| "aeiouAEIOU".contains(*next))
+                    .unwrap_or(false)
+                {
+                    out.push('y');
+                }
+            }
+            _ => out.push(*ch),
+        }
+    }
+
+    out.push_str(SUFFIXES[rand::thread_rng().gen_range(0..SUFFIXES.len())]);
+
+    truncate(out)
+}
+
+fn mock(input: &str) -> String {
+    let mut rng = rand::thread_rng();
+
+    truncate(
+        input
+            .chars()
+            .map(|ch| match rng.gen::<bool>() {
+                true => ch.to_ascii_uppercase(),
+                false => ch.to_ascii_lowercase(),
+            })
+            .collect(),
+    )
+}
+
+fn leet(input: &str) -> String {
+    truncate(
+        input
+            .chars()
+            .map(|ch| match ch.to_ascii_lowercase() {
+                'a' => '4',
+                'e' => '3',
+                'i' => '1',
+                'o' => '0',
+                't' => '7',
+                's' => '5',
+                _ => ch,
+            })
+            .collect(),
+    )
+}
+
+///Parses a `s/pattern/replacement/flags` expression, honoring `\/` as an escaped
+///slash. The flags field is optional.
+fn parse_sed(input: &str) -> Option<(String, String, String)> {
+    let mut chars = input.chars().peekable();
+
+    if chars.next()? != 's' || chars.next()? != '/' {
+        return None;
+    }
+
+    let mut fields: Vec<String> = Vec::new();
+    let mut current = String::new();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' if chars.peek() == Some(&'/') => {
+                current.push('/');
+                chars.next();
+            }
+            '/' => fields.push(std::mem::take(&mut current)),
+            _ => current.push(ch),
+        }
+    }
+    fields.push(current);
+
+    match fields.len() {
+        2 => Some((fields[0].clone(), fields[1].clone(), String::new())),
+        3 => Some((fields[0].clone(), fields[1].clone(), fields[2].clone())),
+        _ => None,
+    }
+}
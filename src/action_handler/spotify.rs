@@ -1,64 +1,311 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use reqwest::Client;
+use regex::Regex;
+use reqwest::{Client, StatusCode};
 use serde_json::Value;
+use tokio::time::sleep;
+
+use crate::db::{DBConn, DBConnError};
+
+///Number of times a request is retried after a rate-limit or auth failure.
+const MAX_RETRIES: u32 = 3;
+
+///How long a cached playback entry is trusted before polling the API again.
+const PLAYBACK_FRESHNESS: Duration = Duration::from_secs(7);
+
+///Locally cached playback state, used to estimate the current position without
+///hitting the Spotify API on every request.
+#[derive(Clone)]
+struct PlaybackInfo {
+    track: String,
+    artists: String,
+    duration_ms: u64,
+    progress_ms: u64,
+    fetched_at: Instant,
+    is_playing: bool,
+}
+
+impl PlaybackInfo {
+    fn render(&self) -> String {
+        let position = match self.is_playing {
+            true => (self.progress_ms + self.fetched_at.elapsed().as_millis() as u64)
+                .min(self.duration_ms),
+            false => self.progress_ms,
+        } / 1000;
+        let length = self.duration_ms / 1000;
+
+        format!(
+            "{} - {} [{}:{:02}/{}:{:02}]",
+            self.artists,
+            self.track,
+            position / 60,
+            position % 60,
+            length / 60,
+            length % 60
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum SpotifyError {
+    Reqwest(reqwest::Error),
+    Db(DBConnError),
+    Parse(serde_json::Error),
+    Api(String),
+    MissingField(String),
+    MaxRetries,
+}
+
+impl From<reqwest::Error> for SpotifyError {
+    fn from(err: reqwest::Error) -> Self {
+        SpotifyError::Reqwest(err)
+    }
+}
+
+impl From<DBConnError> for SpotifyError {
+    fn from(err: DBConnError) -> Self {
+        SpotifyError::Db(err)
+    }
+}
+
+impl From<serde_json::Error> for SpotifyError {
+    fn from(err: serde_json::Error) -> Self {
+        SpotifyError::Parse(err)
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecentlyPlayed {
+    pub items: Vec<PlayHistory>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayHistory {
+    pub track: Track,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Track {
+    pub id: String,
+    pub name: String,
+    pub artists: Vec<Artist>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Artist {
+    pub name: String,
+}
+
+fn join_artists(artists: &Value) -> String {
+    artists
+        .as_array()
+        .map(|artists| {
+            artists
+                .iter()
+                .filter_map(|artist| artist["name"].as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default()
+}
 
 #[derive(Clone)]
 pub struct SpotifyHandler {
     client_id: String,
     client_secret: String,
     client: reqwest::Client,
+    db_conn: DBConn,
+    app_token: Arc<Mutex<Option<(String, Instant)>>>,
+    playback: Arc<Mutex<HashMap<String, PlaybackInfo>>>,
 }
 
 impl SpotifyHandler {
-    pub fn new(client_id: String, client_secret: String) -> SpotifyHandler {
+    pub fn new(client_id: String, client_secret: String, db_conn: DBConn) -> SpotifyHandler {
         SpotifyHandler {
             client_id,
             client_secret,
             client: Client::new(),
+            db_conn,
+            app_token: Arc::new(Mutex::new(None)),
+            playback: Arc::new(Mutex::new(HashMap::new())),
         }
     }
-    pub async fn get_current_song(
-        &self,
-        access_token: &str,
-    ) -> Result<Option<String>, reqwest::Error> {
-        let response = self
+
+    ///Performs an authenticated GET against the Spotify API for a channel,
+    ///backing off on `429 Too Many Requests` and refreshing the stored token on
+    ///`401 Unauthorized`. Returns `Value::Null` for empty `204` responses.
+    async fn request(&self, channel: &str, url: &str) -> Result<Value, SpotifyError> {
+        let (mut access_token, refresh_token) = self.db_conn.get_spotify_access_token(channel)?;
+
+        let mut attempts = 0;
+
+        loop {
+            let response = self
+                .client
+                .get(url)
+                .header("Authorization", format!("Bearer {}", access_token))
+                .send()
+                .await?;
+
+            match response.status() {
+                StatusCode::NO_CONTENT => return Ok(Value::Null),
+                StatusCode::TOO_MANY_REQUESTS => {
+                    if attempts >= MAX_RETRIES {
+                        return Err(SpotifyError::MaxRetries);
+                    }
+                    attempts += 1;
+
+                    let retry_after = response
+                        .headers()
+                        .get("Retry-After")
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.parse::<u64>().ok())
+                        .unwrap_or(1);
+
+                    sleep(Duration::from_secs(retry_after)).await;
+                }
+                StatusCode::UNAUTHORIZED => {
+                    if attempts >= MAX_RETRIES {
+                        return Err(SpotifyError::MaxRetries);
+                    }
+                    attempts += 1;
+
+                    let (new_token, _) = self.update_token(&refresh_token).await?;
+                    self.db_conn.update_spotify_token(channel, &new_token)?;
+                    access_token = new_token;
+                }
+                status if status.is_success() => return Ok(response.json().await?),
+                status => return Err(SpotifyError::Api(format!("unexpected status {}", status))),
+            }
+        }
+    }
+
+    ///Returns a cached app-level access token obtained through the
+    ///client-credentials flow, fetching a fresh one when it has expired.
+    async fn get_app_token(&self) -> Result<String, reqwest::Error> {
+        if let Some((token, expiry)) = self.app_token.lock().unwrap().as_ref() {
+            if Instant::now() < *expiry {
+                return Ok(token.clone());
+            }
+        }
+
+        let mut payload: HashMap<&str, &str> = HashMap::new();
+        payload.insert("grant_type", "client_credentials");
+
+        let response: Value = self
             .client
-            .get("https://api.spotify.com/v1/me/player")
-            .header("Authorization", format!("Bearer {}", access_token))
+            .post("https://accounts.spotify.com/api/token")
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&payload)
             .send()
+            .await?
+            .json()
             .await?;
 
-        match response.json::<Value>().await {
-            Ok(v) => {
-                if let Some(error) = v.get("error") {
-                    Ok(Some(format!("error: {}", error.get("message").unwrap())))
-                } else {
-                    let title = v["item"]["name"].as_str().unwrap();
-
-                    let mut artists: Vec<&str> = Vec::new();
-                    for artist in v["item"]["artists"].as_array().unwrap() {
-                        artists.push(artist["name"].as_str().unwrap());
-                    }
-                    let artists = artists.join(", ");
+        let token = response["access_token"].as_str().unwrap().to_string();
+        let expires_in = response["expires_in"].as_u64().unwrap_or(3600);
 
-                    let position = v["progress_ms"].as_u64().unwrap() / 1000;
-                    let position = format!("{}:{:02}", position / 60, position % 60);
+        *self.app_token.lock().unwrap() =
+            Some((token.clone(), Instant::now() + Duration::from_secs(expires_in)));
 
-                    let length = v["item"]["duration_ms"].as_u64().unwrap() / 1000;
-                    let length = format!("{}:{:02}", length / 60, length % 60);
+        Ok(token)
+    }
 
-                    Ok(Some(format!(
-                        "{} - {} [{}/{}]",
-                        artists, title, position, length
-                    )))
-                }
+    ///Expands a pasted `open.spotify.com/...` or `spotify:...` link into a
+    ///human-readable summary, returning `None` when no link is present.
+    pub async fn resolve_link(&self, text: &str) -> Option<String> {
+        let regex =
+            Regex::new(r"(?:open\.spotify\.com/|spotify:)(track|album|playlist)[/:]([A-Za-z0-9]+)")
+                .unwrap();
+
+        let captures = regex.captures(text)?;
+        let kind = captures.get(1)?.as_str();
+        let id = captures.get(2)?.as_str();
+
+        let token = self.get_app_token().await.ok()?;
+
+        let response: Value = self
+            .client
+            .get(format!("https://api.spotify.com/v1/{}s/{}", kind, id))
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .await
+            .ok()?
+            .json()
+            .await
+            .ok()?;
+
+        if response.get("error").is_some() {
+            return None;
+        }
+
+        match kind {
+            "track" => {
+                let artists = join_artists(&response["artists"]);
+                let length = response["duration_ms"].as_u64().unwrap_or(0) / 1000;
+
+                Some(format!(
+                    "{} - {} [{}:{:02}]",
+                    artists,
+                    response["name"].as_str()?,
+                    length / 60,
+                    length % 60
+                ))
             }
-            Err(_) => {
-                //Nothing is playing
-                Ok(None)
+            "album" => Some(format!(
+                "Album: {} - {} ({} tracks)",
+                join_artists(&response["artists"]),
+                response["name"].as_str()?,
+                response["total_tracks"].as_u64().unwrap_or(0)
+            )),
+            "playlist" => Some(format!(
+                "Playlist: {} by {} ({} tracks)",
+                response["name"].as_str()?,
+                response["owner"]["display_name"].as_str().unwrap_or_default(),
+                response["tracks"]["total"].as_u64().unwrap_or(0)
+            )),
+            _ => None,
+        }
+    }
+    pub async fn get_current_song(&self, channel: &str) -> Result<Option<String>, SpotifyError> {
+        //Serve a locally-estimated position from the cache while it is fresh and playing.
+        if let Some(info) = self.playback.lock().unwrap().get(channel) {
+            if info.is_playing && info.fetched_at.elapsed() < PLAYBACK_FRESHNESS {
+                return Ok(Some(info.render()));
             }
         }
+
+        let value = self
+            .request(channel, "https://api.spotify.com/v1/me/player")
+            .await?;
+
+        if value.is_null() {
+            //Nothing is playing
+            self.playback.lock().unwrap().remove(channel);
+            return Ok(None);
+        }
+
+        let title = value["item"]["name"]
+            .as_str()
+            .ok_or_else(|| SpotifyError::MissingField(String::from("item.name")))?;
+
+        let info = PlaybackInfo {
+            track: title.to_owned(),
+            artists: join_artists(&value["item"]["artists"]),
+            duration_ms: value["item"]["duration_ms"].as_u64().unwrap_or(0),
+            progress_ms: value["progress_ms"].as_u64().unwrap_or(0),
+            fetched_at: Instant::now(),
+            is_playing: value["is_playing"].as_bool().unwrap_or(false),
+        };
+
+        let rendered = info.render();
+        self.playback.lock().unwrap().insert(channel.to_owned(), info);
+
+        Ok(Some(rendered))
     }
 
     pub async fn get_current_playlist(
@@ -87,26 +334,44 @@ impl SpotifyHandler {
         }
     }
 
-    pub async fn get_recently_played(&self, access_token: &str) -> Result<String, reqwest::Error> {
-        match self
-            .client
-            .get("https://api.spotify.com/v1/me/player/recently-played")
-            .header("Authorization", format!("Bearer {}", access_token))
-            .send()
-            .await?
-            .json::<Value>()
-            .await
-        {
-            Ok(recently_played) => {
-                let last_track = &recently_played["items"][0]["track"];
+    ///Walks the recently-played history using the `before` cursor until `count`
+    ///tracks have been collected or the history is exhausted.
+    pub async fn get_recently_played(
+        &self,
+        channel: &str,
+        count: usize,
+    ) -> Result<Vec<Track>, SpotifyError> {
+        let mut tracks: Vec<Track> = Vec::new();
+        let mut before: Option<String> = None;
+
+        while tracks.len() < count {
+            let mut url =
+                String::from("https://api.spotify.com/v1/me/player/recently-played?limit=50");
+            if let Some(before) = &before {
+                url.push_str(&format!("&before={}", before));
+            }
 
-                let artist = last_track["artists"][0]["name"].as_str().unwrap();
-                let song = last_track["name"].as_str().unwrap();
+            let value = self.request(channel, &url).await?;
 
-                Ok(format!("{} - {}", artist, song))
+            let page: RecentlyPlayed = serde_json::from_value(value.clone())?;
+            if page.items.is_empty() {
+                break;
+            }
+
+            for item in page.items {
+                tracks.push(item.track);
+                if tracks.len() >= count {
+                    break;
+                }
+            }
+
+            match value["cursors"]["before"].as_str() {
+                Some(cursor) => before = Some(cursor.to_owned()),
+                None => break,
             }
-            Err(e) => Ok(format!("error getting last song: {:?}", e)),
         }
+
+        Ok(tracks)
     }
 
     ///Returns new access token and the expiration time
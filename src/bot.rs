@@ -1,9 +1,8 @@
-use std::{collections::HashMap, time::Duration};
+use std::{collections::HashMap, time::Instant};
 
 use tokio::{
     sync::mpsc::{self, Receiver, Sender},
     task,
-    time::sleep,
 };
 use twitch_irc::{
     login::StaticLoginCredentials,
@@ -14,8 +13,12 @@ use twitch_irc::{
 use crate::{
     command_handler::{CommandHandler, CommandHandlerError, Permissions},
     db::DBConn,
+    events::EventBus,
+    history::MessageHistory,
     jobs::JobRunner,
+    metrics::Metrics,
     pubsub::PubSubHandler,
+    ratelimit::{RateLimiter, Role},
     twitch_api::TwitchApi,
 };
 
@@ -32,17 +35,32 @@ pub struct Bot {
     command_handler: CommandHandler,
     pubsub_handler: PubSubHandler,
     job_runner: JobRunner,
+    history: MessageHistory,
+    metrics: Metrics,
 }
 
 impl Bot {
-    pub async fn new(db_conn: DBConn) -> anyhow::Result<Self> {
+    pub async fn new(db_conn: DBConn, events: EventBus) -> anyhow::Result<Self> {
         let config = db_conn.get_bot_config()?;
 
         let twitch_api = TwitchApi::init(&config.oauth).await?;
 
-        let command_handler = CommandHandler::new(db_conn.clone(), twitch_api.clone());
-        let pubsub_handler =
-            PubSubHandler::new(TwitchApi::init(&config.oauth).await?, db_conn.clone());
+        let history = MessageHistory::new();
+        let metrics = Metrics::new(&db_conn);
+
+        let command_handler = CommandHandler::new(
+            db_conn.clone(),
+            twitch_api.clone(),
+            history.clone(),
+            metrics.clone(),
+        );
+        let pubsub_handler = PubSubHandler::new(
+            TwitchApi::init(&config.oauth).await?,
+            db_conn.clone(),
+            events,
+            history.clone(),
+            metrics.clone(),
+        );
         let job_runner = JobRunner::new(db_conn);
 
         Ok(Bot {
@@ -50,6 +68,8 @@ impl Bot {
             command_handler,
             pubsub_handler,
             job_runner,
+            history,
+            metrics,
         })
     }
 
@@ -57,15 +77,31 @@ impl Bot {
         let channels = self.config.channels.clone();
         let job_runner = self.job_runner.clone();
         let pubsub_handler = self.pubsub_handler.clone();
+        let metrics = self.metrics.clone();
+
+        metrics.start();
 
         let config = ClientConfig::new_simple(self.config.login.to_owned());
         let (mut incoming_messages, client) =
             TwitchIRCClient::<TCPTransport, StaticLoginCredentials>::new(config);
 
+        //Join-time role state per channel, used to size the send rate limiter.
+        //The bot is the broadcaster in its own channel, where Twitch grants a
+        //higher allowance; everything else starts at the regular limit.
+        let mut roles = HashMap::new();
+        for channel in &channels {
+            let role = match channel == &self.config.login.credentials.login {
+                true => Role::Elevated,
+                false => Role::Normal,
+            };
+            roles.insert(channel.to_owned(), role);
+        }
+
         let (msg_sender, msg_receiver) = mpsc::channel(1000);
         {
             let client = client.clone();
-            task::spawn(async move { Self::listen_msg(msg_receiver, client).await });
+            let metrics = metrics.clone();
+            task::spawn(async move { Self::listen_msg(msg_receiver, client, metrics, roles).await });
         }
 
         let msg_sender1 = msg_sender.clone();
@@ -87,6 +123,7 @@ impl Bot {
         for channel in &channels {
             println!("Joining {}", channel);
             client.join(channel.to_owned());
+            metrics.incr_channels_joined();
             msg_sender
                 .send(SendMsg::Say((
                     channel.to_owned(),
@@ -98,15 +135,20 @@ impl Bot {
 
         // let quit_handle = runner.quit_handle();
 
-        job_runner.start().await?;
+        job_runner.start(msg_sender.clone()).await?;
 
-        task::spawn(async move {
-            loop {
-                pubsub_handler.start(&channels, msg_sender.clone()).await;
-                println!("Pubsub: reconnecting...");
-                sleep(Duration::from_secs(5)).await;
-            }
-        });
+        //Channel-point redemptions are handled over the PubSub socket, which is
+        //the only transport whose payload carries the per-reward cooldown and
+        //cap configuration the redemption limits rely on.
+        {
+            let channels = channels.clone();
+            let client = client.clone();
+            task::spawn(async move {
+                if let Err(e) = pubsub_handler.start(&channels, &client).await {
+                    println!("Pubsub: fatal error: {:?}", e);
+                }
+            });
+        }
 
         join_handle.await?;
 
@@ -114,12 +156,50 @@ impl Bot {
     }
 
     fn handle_privmsg(&self, pm: PrivmsgMessage, msg_sender: Sender<SendMsg>) {
+        if pm.message_text.starts_with("s/") {
+            let command_handler = self.command_handler.clone();
+            let channel = pm.channel_login.clone();
+            let input = pm.message_text.clone();
+
+            task::spawn(async move {
+                if let Some(response) = command_handler.run_sed(&channel, &input) {
+                    msg_sender
+                        .send(SendMsg::Reply((response, pm)))
+                        .await
+                        .expect("Failed to send");
+                }
+            });
+            return;
+        }
+
+        self.metrics.incr_message(&pm.channel_login);
+
+        self.history
+            .push(&pm.channel_login, &pm.sender.login, &pm.message_text);
+
+        if pm.message_text.contains("open.spotify.com/") || pm.message_text.contains("spotify:") {
+            let command_handler = self.command_handler.clone();
+            let channel = pm.channel_login.clone();
+            let text = pm.message_text.clone();
+            let msg_sender = msg_sender.clone();
+
+            task::spawn(async move {
+                if let Some(response) = command_handler.resolve_spotify_link(&text).await {
+                    msg_sender
+                        .send(SendMsg::Say((channel, response)))
+                        .await
+                        .expect("Failed to send");
+                }
+            });
+        }
+
         if let Some(cmd) = self.parse_command(&pm.message_text, &pm.channel_login) {
             println!(
                 "{} {}: {}",
                 pm.channel_login, pm.sender.login, pm.message_text
             );
             let command_handler = self.command_handler.clone();
+            let metrics = self.metrics.clone();
 
             task::spawn(async move {
                 let split = cmd.split_whitespace().collect::<Vec<&str>>();
@@ -132,6 +212,7 @@ impl Bot {
                         if let Some(cmd) = cmd {
                             if Self::check_command_permissions(&pm, &cmd.permissions) {
                                 println!("Executing {:?}", cmd.action);
+                                metrics.incr_command_trigger(trigger, &pm.channel_login);
 
                                 match command_handler
                                     .run_command(
@@ -178,6 +259,7 @@ impl Bot {
                                     },
                                 }
                             } else {
+                                metrics.incr_permission_denial();
                                 msg_sender
                                     .send(SendMsg::Reply((
                                         "you do not have the permissions to use this command!"
@@ -231,24 +313,43 @@ impl Bot {
     async fn listen_msg(
         mut receiver: Receiver<SendMsg>,
         client: TwitchIRCClient<TCPTransport, StaticLoginCredentials>,
+        metrics: Metrics,
+        roles: HashMap<String, Role>,
     ) {
         println!("Starting message queue receiver");
+        let mut limiter = RateLimiter::new(roles);
+
         while let Some(msg) = receiver.recv().await {
+            let channel = match &msg {
+                SendMsg::Say((channel, _)) => channel.clone(),
+                SendMsg::Reply((_, reply_to)) => reply_to.channel_login.clone(),
+                SendMsg::Raw((channel, _)) => channel.clone(),
+            };
+
+            //Block only as long as the per-channel and global buckets require.
+            limiter.acquire(&channel).await;
+
+            let started = Instant::now();
+
             match msg {
                 SendMsg::Say((channel, message)) => {
-                    client.say(channel, message).await.expect("Failed to say")
+                    client.say(channel, message).await.expect("Failed to say");
+                }
+                SendMsg::Reply((message, reply_to)) => {
+                    client
+                        .reply_to_privmsg(message, &reply_to)
+                        .await
+                        .expect("Failed to reply");
+                }
+                SendMsg::Raw((channel, message)) => {
+                    client
+                        .privmsg(channel, message)
+                        .await
+                        .expect("Failed to privmsg");
                 }
-                SendMsg::Reply((message, reply_to)) => client
-                    .reply_to_privmsg(message, &reply_to)
-                    .await
-                    .expect("Failed to reply"),
-                SendMsg::Raw((channel, message)) => client
-                    .privmsg(channel, message)
-                    .await
-                    .expect("Failed to privmsg"),
             }
 
-            tokio::time::sleep(Duration::from_millis(1000)).await;
+            metrics.record_send_latency(&channel, started.elapsed().as_millis() as u64);
         }
         println!("Error receiving message for sending!");
     }
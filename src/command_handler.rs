@@ -1,7 +1,7 @@
 use anyhow::Result;
 use tokio::sync::mpsc::Sender;
 
-use crate::{action_handler::{Action, ActionHandler}, bot::SendMsg, db::{DBConn, DBConnError}, twitch_api::TwitchApi};
+use crate::{action_handler::{Action, ActionHandler}, bot::SendMsg, db::{DBConn, DBConnError}, history::MessageHistory, metrics::Metrics, twitch_api::TwitchApi};
 
 #[derive(Clone, Debug)]
 pub enum Permissions {
@@ -75,14 +75,27 @@ pub struct CommandHandler {
 }
 
 impl CommandHandler {
-    pub fn new(db_conn: DBConn, twitch_api: TwitchApi) -> Self {
-        let action_handler = ActionHandler::new(db_conn.clone(), twitch_api);
+    pub fn new(
+        db_conn: DBConn,
+        twitch_api: TwitchApi,
+        history: MessageHistory,
+        metrics: Metrics,
+    ) -> Self {
+        let action_handler = ActionHandler::new(db_conn.clone(), twitch_api, history, metrics);
         Self {
             db_conn,
             action_handler,
         }
     }
 
+    pub fn run_sed(&self, channel: &str, input: &str) -> Option<String> {
+        self.action_handler.sed(channel, input)
+    }
+
+    pub async fn resolve_spotify_link(&self, text: &str) -> Option<String> {
+        self.action_handler.resolve_spotify_link(text).await
+    }
+
     pub fn get_command(
         &self,
         trigger: &str,
@@ -264,7 +277,7 @@ impl CommandHandler {
 
                         match self
                             .action_handler
-                            .run(action, &action_args, channel, msg_sender.clone())
+                            .run(action, &action_args, channel, runner, msg_sender.clone())
                             .await
                         {
                             Ok(Some(action_response)) => response.push_str(&action_response),
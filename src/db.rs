@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use mysql::{params, prelude::Queryable, Pool};
 use std::{collections::HashMap, fmt};
 use twitch_irc::login::StaticLoginCredentials;
@@ -36,6 +37,22 @@ impl fmt::Display for DBConnError {
 
 impl std::error::Error for DBConnError {}
 
+#[derive(Debug, Clone)]
+pub struct Quote {
+    pub id: u64,
+    pub author: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Reminder {
+    pub id: u64,
+    pub channel: String,
+    pub target_user: String,
+    pub set_by: String,
+    pub message: String,
+}
+
 #[derive(Debug, Clone)]
 pub struct DBConn {
     pool: Pool,
@@ -228,6 +245,44 @@ impl DBConn {
         }
     }
 
+    ///Whether a redeem whose action failed (or that has no action) should be
+    ///cancelled and refunded rather than marked fulfilled. Defaults to `true`.
+    pub fn get_redeem_cancel_on_failure(
+        &self,
+        name: &str,
+        channel: &str,
+    ) -> Result<bool, DBConnError> {
+        let mut conn = self.pool.get_conn()?;
+
+        Ok(conn
+            .exec_first(
+                "SELECT cancel_on_failure FROM redeem_triggers WHERE name = :name AND channel = :channel",
+                params! {
+                    name, channel,
+                },
+            )?
+            .unwrap_or(true))
+    }
+
+    ///Whether the action bound to a reward may only run while the channel is
+    ///live. Defaults to `false` so existing redeems are unaffected.
+    pub fn get_redeem_requires_live(
+        &self,
+        name: &str,
+        channel: &str,
+    ) -> Result<bool, DBConnError> {
+        let mut conn = self.pool.get_conn()?;
+
+        Ok(conn
+            .exec_first(
+                "SELECT requires_live FROM redeem_triggers WHERE name = :name AND channel = :channel",
+                params! {
+                    name, channel,
+                },
+            )?
+            .unwrap_or(false))
+    }
+
     pub fn add_hitman(&self, username: &str, channel: &str) -> Result<(), DBConnError> {
         let mut conn = self.pool.get_conn()?;
 
@@ -272,6 +327,157 @@ impl DBConn {
             })?)
     }
 
+    pub fn get_eval_result(&self, channel: &str, username: &str) -> Result<f64, DBConnError> {
+        let mut conn = self.pool.get_conn()?;
+
+        match conn.exec_first(
+            "SELECT result FROM eval_state WHERE channel = :channel AND name = :name",
+            params! {
+                "channel" => channel,
+                "name" => username,
+            },
+        )? {
+            Some(result) => Ok(result),
+            None => Ok(0.0),
+        }
+    }
+
+    pub fn set_eval_result(
+        &self,
+        channel: &str,
+        username: &str,
+        result: &f64,
+    ) -> Result<(), DBConnError> {
+        let mut conn = self.pool.get_conn()?;
+
+        Ok(conn.exec_drop(
+            "INSERT INTO eval_state VALUES (:channel, :name, :result) ON DUPLICATE KEY UPDATE result = :result",
+            params! {
+                "channel" => channel,
+                "name" => username,
+                "result" => result,
+            },
+        )?)
+    }
+
+    pub fn add_quote(
+        &self,
+        channel: &str,
+        author: &str,
+        text: &str,
+        grabbed_by: &str,
+    ) -> Result<(), DBConnError> {
+        let mut conn = self.pool.get_conn()?;
+
+        conn.exec_drop(
+            "INSERT INTO quotes (channel, author, text, grabbed_by, created_at) VALUES (:channel, :author, :text, :grabbed_by, NOW())",
+            params! {
+                "channel" => channel,
+                "author" => author,
+                "text" => text,
+                "grabbed_by" => grabbed_by,
+            },
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_quote_by_id(&self, channel: &str, id: u64) -> Result<Option<Quote>, DBConnError> {
+        let mut conn = self.pool.get_conn()?;
+
+        Ok(conn
+            .exec_map(
+                "SELECT id, author, text FROM quotes WHERE channel = :channel AND id = :id",
+                params! {
+                    "channel" => channel,
+                    "id" => id,
+                },
+                |(id, author, text)| Quote { id, author, text },
+            )?
+            .first()
+            .cloned())
+    }
+
+    pub fn search_quotes(&self, channel: &str, term: &str) -> Result<Vec<Quote>, DBConnError> {
+        let mut conn = self.pool.get_conn()?;
+
+        Ok(conn.exec_map(
+            "SELECT id, author, text FROM quotes WHERE channel = :channel AND text LIKE :term",
+            params! {
+                "channel" => channel,
+                "term" => format!("%{}%", term),
+            },
+            |(id, author, text)| Quote { id, author, text },
+        )?)
+    }
+
+    pub fn get_random_quote(&self, channel: &str) -> Result<Option<Quote>, DBConnError> {
+        let mut conn = self.pool.get_conn()?;
+
+        Ok(conn
+            .exec_map(
+                "SELECT id, author, text FROM quotes WHERE channel = :channel ORDER BY RAND() LIMIT 1",
+                params! {
+                    "channel" => channel,
+                },
+                |(id, author, text)| Quote { id, author, text },
+            )?
+            .first()
+            .cloned())
+    }
+
+    pub fn add_reminder(
+        &self,
+        channel: &str,
+        target_user: &str,
+        set_by: &str,
+        message: &str,
+        remind_at: &DateTime<Utc>,
+    ) -> Result<(), DBConnError> {
+        let mut conn = self.pool.get_conn()?;
+
+        conn.exec_drop(
+            "INSERT INTO reminders (channel, target_user, set_by, message, remind_at) VALUES (:channel, :target_user, :set_by, :message, :remind_at)",
+            params! {
+                "channel" => channel,
+                "target_user" => target_user,
+                "set_by" => set_by,
+                "message" => message,
+                "remind_at" => remind_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+            },
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_due_reminders(&self) -> Result<Vec<Reminder>, DBConnError> {
+        let mut conn = self.pool.get_conn()?;
+
+        Ok(conn.query_map(
+            "SELECT id, channel, target_user, set_by, message FROM reminders WHERE remind_at <= UTC_TIMESTAMP()",
+            |(id, channel, target_user, set_by, message)| Reminder {
+                id,
+                channel,
+                target_user,
+                set_by,
+                message,
+            },
+        )?)
+    }
+
+    pub fn del_reminder(&self, id: u64) -> Result<(), DBConnError> {
+        let mut conn = self.pool.get_conn()?;
+
+        conn.exec_drop(
+            "DELETE FROM reminders WHERE id = :id",
+            params! {
+                "id" => id,
+            },
+        )?;
+
+        Ok(())
+    }
+
     ///Retruns a tuple of an access token and a refresh token.
     pub fn get_spotify_access_token(&self, channel: &str) -> Result<(String, String), DBConnError> {
         let mut conn = self.pool.get_conn()?;
@@ -323,6 +529,12 @@ impl DBConn {
         Ok(conn.query_first("SELECT value FROM settings WHERE option = \"openweathermap\"")?.unwrap_or_default())
     }
 
+    pub fn get_metrics_url(&self) -> Result<String, DBConnError> {
+        let mut conn = self.pool.get_conn()?;
+
+        Ok(conn.query_first("SELECT value FROM settings WHERE option = \"metrics_url\"")?.unwrap_or_default())
+    }
+
     pub fn get_spotify_cilent_id(&self) -> Result<String, DBConnError> {
         let mut conn = self.pool.get_conn()?;
 
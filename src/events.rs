@@ -0,0 +1,56 @@
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+///Number of events buffered per subscriber before the slowest ones start
+///lagging; plenty for a dashboard that only cares about recent activity.
+const CAPACITY: usize = 256;
+
+///Typed activity events published by the ingestion loops and consumed by any
+///number of independent subscribers (logging, metrics, the web dashboard).
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum BotEvent {
+    RedeemReceived {
+        channel: String,
+        user: String,
+        reward: String,
+    },
+    ActionExecuted {
+        channel: String,
+        reward: String,
+        response: Option<String>,
+    },
+    Reconnected,
+    ParseError {
+        message: String,
+    },
+    StreamUp {
+        channel: String,
+    },
+    StreamDown {
+        channel: String,
+    },
+}
+
+///A fan-out bus backed by a [`tokio::sync::broadcast`] channel. Cloning hands
+///out another publisher onto the same channel.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: Sender<BotEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender }
+    }
+
+    ///Publishes an event to all current subscribers. Sending with no
+    ///subscribers attached is not an error and is silently dropped.
+    pub fn publish(&self, event: BotEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> Receiver<BotEvent> {
+        self.sender.subscribe()
+    }
+}
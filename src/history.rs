@@ -0,0 +1,40 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+const HISTORY_SIZE: usize = 50;
+
+#[derive(Clone)]
+pub struct MessageHistory {
+    messages: Arc<Mutex<HashMap<String, VecDeque<(String, String)>>>>,
+}
+
+impl MessageHistory {
+    pub fn new() -> Self {
+        MessageHistory {
+            messages: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn push(&self, channel: &str, user: &str, text: &str) {
+        let mut messages = self.messages.lock().unwrap();
+        let buffer = messages.entry(channel.to_owned()).or_default();
+
+        buffer.push_back((user.to_owned(), text.to_owned()));
+
+        while buffer.len() > HISTORY_SIZE {
+            buffer.pop_front();
+        }
+    }
+
+    ///Returns the buffered messages for a channel, oldest first.
+    pub fn recent(&self, channel: &str) -> Vec<(String, String)> {
+        self.messages
+            .lock()
+            .unwrap()
+            .get(channel)
+            .map(|buffer| buffer.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
@@ -1,10 +1,11 @@
 use std::time::Duration;
 
 use action_handler::spotify::SpotifyHandler;
-use tokio::{task, time::sleep};
+use tokio::{sync::mpsc::Sender, task, time::sleep};
 
 use crate::{
     action_handler,
+    bot::SendMsg,
     db::{DBConn, DBConnError},
 };
 
@@ -19,6 +20,7 @@ impl JobRunner {
         let spotify_handler = SpotifyHandler::new(
             db_pool.get_spotify_cilent_id().unwrap(),
             db_pool.get_spotify_client_secret().unwrap(),
+            db_pool.clone(),
         );
         JobRunner {
             db_pool,
@@ -26,7 +28,7 @@ impl JobRunner {
         }
     }
 
-    pub async fn start(&self) -> Result<(), DBConnError> {
+    pub async fn start(&self, msg_sender: Sender<SendMsg>) -> Result<(), DBConnError> {
         let tokens = self.db_pool.get_spotify_refresh_tokens()?;
 
         for (channel, refresh_token) in tokens {
@@ -34,9 +36,47 @@ impl JobRunner {
                 .await;
         }
 
+        self.start_reminders(msg_sender);
+
         Ok(())
     }
 
+    fn start_reminders(&self, msg_sender: Sender<SendMsg>) {
+        let db_pool = self.db_pool.clone();
+        task::spawn(async move {
+            loop {
+                match db_pool.get_due_reminders() {
+                    Ok(reminders) => {
+                        for reminder in reminders {
+                            let text = format!(
+                                "@{}: {} (from {})",
+                                reminder.target_user, reminder.message, reminder.set_by
+                            );
+
+                            if msg_sender
+                                .send(SendMsg::Say((reminder.channel.clone(), text)))
+                                .await
+                                .is_err()
+                            {
+                                break;
+                            }
+
+                            if let Err(err) = db_pool.del_reminder(reminder.id) {
+                                println!(
+                                    "DB error {:?} when deleting reminder {}",
+                                    err, reminder.id
+                                );
+                            }
+                        }
+                    }
+                    Err(err) => println!("DB error {:?} when fetching reminders", err),
+                }
+
+                sleep(Duration::from_secs(5)).await;
+            }
+        });
+    }
+
     async fn start_spotify_token_refresh(&self, channel: String, refresh_token: String) {
         let db_pool = self.db_pool.clone();
         let spotify_handler = self.spotify_handler.clone();
@@ -10,14 +10,19 @@ mod bot;
 mod command_handler;
 mod config;
 mod db;
+mod events;
+mod history;
 mod jobs;
+mod metrics;
 mod pubsub;
+mod ratelimit;
 mod twitch_api;
 mod web;
 
 use bot::Bot;
 use config::DBConfig;
 use db::DBConn;
+use events::EventBus;
 use tokio::{fs, task};
 
 #[tokio::main]
@@ -29,14 +34,17 @@ async fn main() -> anyhow::Result<()> {
 
     let db_conn = DBConn::new(&db_config)?;
 
+    let events = EventBus::new();
+
     {
         let db_conn = db_conn.clone();
+        let events = events.clone();
         task::spawn(async move {
-            web::run(db_conn);
+            web::run(db_conn, events);
         });
     }
 
-    let bot = Bot::new(db_conn).await?;
+    let bot = Bot::new(db_conn, events).await?;
     bot.run().await?;
 
     Ok(())
@@ -0,0 +1,201 @@
+//! Opt-in activity metrics exported to a Prometheus Pushgateway.
+//!
+//! The whole subsystem is a no-op unless the `metrics` feature is enabled, so
+//! call sites can increment counters unconditionally.
+
+#[cfg(feature = "metrics")]
+mod inner {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use reqwest::Client;
+    use tokio::{task, time::sleep};
+
+    use crate::db::DBConn;
+
+    #[derive(Clone)]
+    pub struct Metrics {
+        commands: Arc<Mutex<HashMap<String, u64>>>,
+        commands_by_trigger: Arc<Mutex<HashMap<(String, String), u64>>>,
+        messages: Arc<Mutex<HashMap<String, u64>>>,
+        api_calls: Arc<Mutex<HashMap<String, u64>>>,
+        send_latency: Arc<Mutex<HashMap<String, u64>>>,
+        channels_joined: Arc<Mutex<u64>>,
+        permission_denials: Arc<Mutex<u64>>,
+        spotify_errors: Arc<Mutex<u64>>,
+        client: Client,
+        url: Option<String>,
+    }
+
+    fn bump(map: &Arc<Mutex<HashMap<String, u64>>>, key: &str) {
+        *map.lock().unwrap().entry(key.to_owned()).or_insert(0) += 1;
+    }
+
+    fn render_counter(out: &mut String, name: &str, label: &str, map: &Arc<Mutex<HashMap<String, u64>>>) {
+        out.push_str(&format!("# TYPE {} counter\n", name));
+        for (key, value) in map.lock().unwrap().iter() {
+            out.push_str(&format!("{}{{{}=\"{}\"}} {}\n", name, label, key, value));
+        }
+    }
+
+    impl Metrics {
+        pub fn new(db_conn: &DBConn) -> Self {
+            let url = db_conn
+                .get_metrics_url()
+                .ok()
+                .filter(|url| !url.is_empty());
+
+            Metrics {
+                commands: Arc::new(Mutex::new(HashMap::new())),
+                commands_by_trigger: Arc::new(Mutex::new(HashMap::new())),
+                messages: Arc::new(Mutex::new(HashMap::new())),
+                api_calls: Arc::new(Mutex::new(HashMap::new())),
+                send_latency: Arc::new(Mutex::new(HashMap::new())),
+                channels_joined: Arc::new(Mutex::new(0)),
+                permission_denials: Arc::new(Mutex::new(0)),
+                spotify_errors: Arc::new(Mutex::new(0)),
+                client: Client::new(),
+                url,
+            }
+        }
+
+        pub fn incr_command(&self, action: &str) {
+            bump(&self.commands, action);
+        }
+
+        pub fn incr_command_trigger(&self, trigger: &str, channel: &str) {
+            *self
+                .commands_by_trigger
+                .lock()
+                .unwrap()
+                .entry((trigger.to_owned(), channel.to_owned()))
+                .or_insert(0) += 1;
+        }
+
+        pub fn incr_message(&self, channel: &str) {
+            bump(&self.messages, channel);
+        }
+
+        pub fn incr_api_call(&self, name: &str) {
+            bump(&self.api_calls, name);
+        }
+
+        pub fn incr_permission_denial(&self) {
+            *self.permission_denials.lock().unwrap() += 1;
+        }
+
+        pub fn incr_spotify_error(&self) {
+            *self.spotify_errors.lock().unwrap() += 1;
+        }
+
+        pub fn record_send_latency(&self, channel: &str, millis: u64) {
+            self.send_latency
+                .lock()
+                .unwrap()
+                .insert(channel.to_owned(), millis);
+        }
+
+        pub fn incr_channels_joined(&self) {
+            *self.channels_joined.lock().unwrap() += 1;
+        }
+
+        pub fn start(&self) {
+            let metrics = self.clone();
+            let url = match &self.url {
+                Some(url) => format!("{}/metrics/job/foobot", url),
+                None => return,
+            };
+
+            task::spawn(async move {
+                loop {
+                    sleep(Duration::from_secs(15)).await;
+
+                    if let Err(err) = metrics.client.put(&url).body(metrics.render()).send().await {
+                        println!("Metrics: failed to push to pushgateway: {:?}", err);
+                    }
+                }
+            });
+        }
+
+        fn render(&self) -> String {
+            let mut out = String::new();
+
+            render_counter(&mut out, "foobot_commands_total", "action", &self.commands);
+            render_counter(&mut out, "foobot_messages_total", "channel", &self.messages);
+            render_counter(&mut out, "foobot_api_calls_total", "api", &self.api_calls);
+
+            out.push_str("# TYPE foobot_commands_by_trigger_total counter\n");
+            for ((trigger, channel), value) in self.commands_by_trigger.lock().unwrap().iter() {
+                out.push_str(&format!(
+                    "foobot_commands_by_trigger_total{{trigger=\"{}\",channel=\"{}\"}} {}\n",
+                    trigger, channel, value
+                ));
+            }
+
+            out.push_str("# TYPE foobot_send_latency_ms gauge\n");
+            for (channel, value) in self.send_latency.lock().unwrap().iter() {
+                out.push_str(&format!(
+                    "foobot_send_latency_ms{{channel=\"{}\"}} {}\n",
+                    channel, value
+                ));
+            }
+
+            out.push_str("# TYPE foobot_channels_joined gauge\n");
+            out.push_str(&format!(
+                "foobot_channels_joined {}\n",
+                self.channels_joined.lock().unwrap()
+            ));
+
+            out.push_str("# TYPE foobot_permission_denials_total counter\n");
+            out.push_str(&format!(
+                "foobot_permission_denials_total {}\n",
+                self.permission_denials.lock().unwrap()
+            ));
+
+            out.push_str("# TYPE foobot_spotify_errors_total counter\n");
+            out.push_str(&format!(
+                "foobot_spotify_errors_total {}\n",
+                self.spotify_errors.lock().unwrap()
+            ));
+
+            out
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod inner {
+    use crate::db::DBConn;
+
+    #[derive(Clone)]
+    pub struct Metrics;
+
+    impl Metrics {
+        pub fn new(_db_conn: &DBConn) -> Self {
+            Metrics
+        }
+
+        pub fn incr_command(&self, _action: &str) {}
+
+        pub fn incr_command_trigger(&self, _trigger: &str, _channel: &str) {}
+
+        pub fn incr_message(&self, _channel: &str) {}
+
+        pub fn incr_api_call(&self, _name: &str) {}
+
+        pub fn incr_permission_denial(&self) {}
+
+        pub fn incr_spotify_error(&self) {}
+
+        pub fn record_send_latency(&self, _channel: &str, _millis: u64) {}
+
+        pub fn incr_channels_joined(&self) {}
+
+        pub fn start(&self) {}
+    }
+}
+
+pub use inner::Metrics;
@@ -1,8 +1,15 @@
 pub mod channel_points;
 
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
-use channel_points::ChannelPointsRedeem;
+use channel_points::{ChannelPointsRedeem, Reward};
 use futures_util::{SinkExt, StreamExt};
 use reqwest::Url;
 use serde_json::{json, Value};
@@ -14,6 +21,9 @@ use crate::{
     action_handler::Action,
     command_handler::{Command, CommandHandler},
     db::DBConn,
+    events::{BotEvent, EventBus},
+    history::MessageHistory,
+    metrics::Metrics,
     twitch_api::TwitchApi,
 };
 
@@ -37,23 +47,254 @@ pub struct PubSubHandler {
     twitch_api: TwitchApi,
     command_handler: CommandHandler,
     db_conn: DBConn,
+    events: EventBus,
+    global_cooldowns: Arc<RwLock<HashMap<(String, String), Instant>>>,
+    user_cooldowns: Arc<RwLock<HashMap<(String, String), Instant>>>,
+    stream_counts: Arc<RwLock<HashMap<(String, String), u64>>>,
+    user_stream_counts: Arc<RwLock<HashMap<(String, String), u64>>>,
+    live: Arc<RwLock<HashMap<String, AtomicBool>>>,
 }
 
 impl PubSubHandler {
-    pub fn new(twitch_api: TwitchApi, db_conn: DBConn) -> Self {
-        let command_handler = CommandHandler::new(db_conn.clone(), twitch_api.clone());
+    pub fn new(
+        twitch_api: TwitchApi,
+        db_conn: DBConn,
+        events: EventBus,
+        history: MessageHistory,
+        metrics: Metrics,
+    ) -> Self {
+        let command_handler =
+            CommandHandler::new(db_conn.clone(), twitch_api.clone(), history, metrics);
         Self {
             twitch_api,
             command_handler,
             db_conn,
+            events,
+            global_cooldowns: Arc::new(RwLock::new(HashMap::new())),
+            user_cooldowns: Arc::new(RwLock::new(HashMap::new())),
+            stream_counts: Arc::new(RwLock::new(HashMap::new())),
+            user_stream_counts: Arc::new(RwLock::new(HashMap::new())),
+            live: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
+    ///Whether a channel is currently streaming, according to the last poll.
+    ///Unknown channels are treated as offline.
+    fn is_live(&self, channel: &str) -> bool {
+        self.live
+            .read()
+            .unwrap()
+            .get(channel)
+            .map(|state| state.load(Ordering::Relaxed))
+            .unwrap_or(false)
+    }
+
+    ///Polls Helix `streams` for the tracked channels every minute, updating the
+    ///live map and broadcasting up/down transitions. A channel coming online
+    ///also restarts its per-stream redemption counters.
+    fn start_stream_polling(&self, channels: Vec<String>) {
+        let handler = self.clone();
+
+        task::spawn(async move {
+            loop {
+                match handler.twitch_api.get_live_channels(&channels).await {
+                    Ok(online) => {
+                        for channel in &channels {
+                            let now_live = online.iter().any(|login| login == channel);
+
+                            let was_live = {
+                                let map = handler.live.read().unwrap();
+                                match map.get(channel) {
+                                    Some(state) => {
+                                        state.swap(now_live, Ordering::Relaxed)
+                                    }
+                                    None => {
+                                        drop(map);
+                                        handler
+                                            .live
+                                            .write()
+                                            .unwrap()
+                                            .insert(channel.clone(), AtomicBool::new(now_live));
+                                        now_live
+                                    }
+                                }
+                            };
+
+                            if now_live && !was_live {
+                                handler.reset_stream_counters(channel);
+                                handler.events.publish(BotEvent::StreamUp {
+                                    channel: channel.clone(),
+                                });
+                            } else if !now_live && was_live {
+                                handler.events.publish(BotEvent::StreamDown {
+                                    channel: channel.clone(),
+                                });
+                            }
+                        }
+                    }
+                    Err(e) => println!("Pubsub: failed to poll stream status: {:?}", e),
+                }
+
+                sleep(Duration::from_secs(60)).await;
+            }
+        });
+    }
+
+    ///Returns whether the redeem is allowed right now under the reward's global
+    ///cooldown, per-user cooldown and the `max_per_stream` /
+    ///`max_per_user_per_stream` caps. This is a read-only check; the counters and
+    ///cooldown timestamps are only advanced by [`Self::record_redemption`] once a
+    ///redeem is actually kept, so a refunded redeem neither consumes a use nor
+    ///arms the cooldown.
+    fn within_limits(&self, reward: &Reward, channel: &str, user_id: &str) -> bool {
+        let now = Instant::now();
+        let global_key = (reward.id.clone(), channel.to_owned());
+        let user_key = (reward.id.clone(), user_id.to_owned());
+
+        if reward.global_cooldown.is_enabled {
+            let cooldown =
+                Duration::from_secs(reward.global_cooldown.global_cooldown_seconds as u64);
+
+            if let Some(last) = self.global_cooldowns.read().unwrap().get(&global_key) {
+                if now.duration_since(*last) < cooldown {
+                    return false;
+                }
+            }
+            if let Some(last) = self.user_cooldowns.read().unwrap().get(&user_key) {
+                if now.duration_since(*last) < cooldown {
+                    return false;
+                }
+            }
+        }
+
+        if reward.max_per_stream.is_enabled {
+            let count = self
+                .stream_counts
+                .read()
+                .unwrap()
+                .get(&global_key)
+                .copied()
+                .unwrap_or(0);
+            if count >= reward.max_per_stream.max_per_stream as u64 {
+                return false;
+            }
+        }
+        if reward.max_per_user_per_stream.is_enabled {
+            let count = self
+                .user_stream_counts
+                .read()
+                .unwrap()
+                .get(&user_key)
+                .copied()
+                .unwrap_or(0);
+            if count >= reward.max_per_user_per_stream.max_per_user_per_stream as u64 {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    ///Records a kept redeem against the per-stream caps and arms the global and
+    ///per-user cooldowns. Called only after a redeem is fulfilled, so refunded
+    ///redeems do not count towards the limits.
+    fn record_redemption(&self, reward: &Reward, channel: &str, user_id: &str) {
+        let now = Instant::now();
+        let global_key = (reward.id.clone(), channel.to_owned());
+        let user_key = (reward.id.clone(), user_id.to_owned());
+
+        if reward.global_cooldown.is_enabled {
+            self.global_cooldowns
+                .write()
+                .unwrap()
+                .insert(global_key.clone(), now);
+            self.user_cooldowns
+                .write()
+                .unwrap()
+                .insert(user_key.clone(), now);
+        }
+        *self
+            .stream_counts
+            .write()
+            .unwrap()
+            .entry(global_key)
+            .or_insert(0) += 1;
+        *self
+            .user_stream_counts
+            .write()
+            .unwrap()
+            .entry(user_key)
+            .or_insert(0) += 1;
+    }
+
+    ///Clears the per-stream limit state for a channel, called when a new stream
+    ///starts so cooldowns and counters restart with the broadcast. The per-user
+    ///maps are not keyed by channel, so they are cleared wholesale.
+    pub fn reset_stream_counters(&self, channel: &str) {
+        self.global_cooldowns
+            .write()
+            .unwrap()
+            .retain(|(_, c), _| c != channel);
+        self.stream_counts
+            .write()
+            .unwrap()
+            .retain(|(_, c), _| c != channel);
+        self.user_cooldowns.write().unwrap().clear();
+        self.user_stream_counts.write().unwrap().clear();
+    }
+
+    ///Supervises the PubSub connection for its whole lifetime: on any drop,
+    ///write error or `RECONNECT` control message it reconnects with exponential
+    ///backoff (1s doubling up to 60s), resetting the backoff once a connection
+    ///has stayed up for over a minute.
     pub async fn start(
         &self,
         channels: &Vec<String>,
         client: &TwitchIRCClient<TCPTransport, StaticLoginCredentials>,
     ) -> anyhow::Result<()> {
+        const MAX_BACKOFF: Duration = Duration::from_secs(60);
+        let mut backoff = Duration::from_secs(1);
+
+        self.start_stream_polling(channels.clone());
+
+        loop {
+            let started = Instant::now();
+            let result = self.connect_and_listen(channels, client).await;
+
+            match result {
+                Ok(()) => println!("Pubsub: connection closed, reconnecting"),
+                Err(e) => println!("Pubsub: connection error: {:?}", e),
+            }
+
+            //A connection that stayed up for over a minute is considered
+            //healthy, so the backoff resets and we reconnect promptly. Anything
+            //shorter — including an immediate clean close — is treated as a flap
+            //and delayed, so a server that accepts then instantly drops us does
+            //not get hammered in a tight loop.
+            if started.elapsed() > Duration::from_secs(60) {
+                backoff = Duration::from_secs(1);
+            } else {
+                println!("Pubsub: reconnecting in {:?}", backoff);
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+
+    ///Opens a single socket, subscribes to the channel-point topics and pumps
+    ///messages until the connection drops or Twitch asks us to reconnect.
+    ///Returns `Ok(())` on a clean close/`RECONNECT`, `Err` on a transport error.
+    async fn connect_and_listen(
+        &self,
+        channels: &Vec<String>,
+        client: &TwitchIRCClient<TCPTransport, StaticLoginCredentials>,
+    ) -> anyhow::Result<()> {
+        //Re-validate the LISTEN auth token when it is near expiry; an expired
+        //token fails here instead of at LISTEN. The token is not renewed.
+        self.twitch_api
+            .refresh_token_if_expiring(Duration::from_secs(15 * 60))
+            .await?;
+
         let mut topics: Vec<String> = Vec::new();
 
         for user in self.twitch_api.get_users_by_login(channels).await?.data {
@@ -70,6 +311,7 @@ impl PubSubHandler {
             "Pubsub connection established, status {}",
             response.status()
         );
+        self.events.publish(BotEvent::Reconnected);
 
         let auth = json!({
             "type": "LISTEN",
@@ -78,50 +320,53 @@ impl PubSubHandler {
                 "auth_token": &self.twitch_api.get_oauth(),
             },
         });
-        println!("Pubsub: using {}", auth.to_string());
 
         write.send(Message::Text(auth.to_string())).await?;
 
-        task::spawn(async move {
-            loop {
-                println!("Pubsub: sending ping");
-                match write.send(Message::Ping(vec![].into())).await {
-                    Ok(_) => sleep(Duration::from_secs(60)).await,
-                    Err(_) => break,
-                };
-            }
-        });
+        let mut ping_interval = tokio::time::interval(Duration::from_secs(60));
+        ping_interval.tick().await; //consume the immediate first tick
 
-        {
-            let client = client.clone();
-            let handler = self.clone();
-            task::spawn(async move {
-                while let Some(msg) = read.next().await {
+        loop {
+            tokio::select! {
+                _ = ping_interval.tick() => {
+                    println!("Pubsub: sending ping");
+                    write.send(Message::Ping(vec![].into())).await?;
+                }
+                msg = read.next() => {
                     match msg {
-                        Ok(msg) => match msg {
-                            Message::Ping(_) => println!("Pubsub: recieved PING"),
-                            Message::Pong(_) => println!("Pubsub: recieved PONG"),
-                            Message::Text(text) => {
-                                // println!("Pubsub message: {}", text);
-                                if let Ok(v) = serde_json::from_str::<Value>(&text) {
-                                    let handler = handler.clone();
+                        None => return Ok(()),
+                        Some(Err(e)) => return Err(e.into()),
+                        Some(Ok(Message::Text(text))) => {
+                            match serde_json::from_str::<Value>(&text) {
+                                Ok(v) => {
+                                    //Twitch sends this before maintenance; close and reconnect cleanly.
+                                    if v["type"] == "RECONNECT" {
+                                        println!("Pubsub: received RECONNECT");
+                                        return Ok(());
+                                    }
+                                    let handler = self.clone();
                                     let client = client.clone();
                                     task::spawn(async move {
-                                        handler.handle_msg(v, client).await.unwrap();
+                                        if let Err(e) = handler.handle_msg(v, client).await {
+                                            println!("Pubsub: error handling message: {:?}", e);
+                                        }
                                     });
                                 }
+                                Err(e) => {
+                                    println!("Pubsub: failed to parse message: {:?}", e);
+                                    self.events
+                                        .publish(BotEvent::ParseError { message: e.to_string() });
+                                }
                             }
-                            _ => continue,
-                        },
-                        Err(e) => println!("Errror reading pubsub message: {:?}", e),
+                        }
+                        Some(Ok(Message::Close(_))) => return Ok(()),
+                        Some(Ok(Message::Ping(_))) => println!("Pubsub: recieved PING"),
+                        Some(Ok(Message::Pong(_))) => println!("Pubsub: recieved PONG"),
+                        Some(Ok(_)) => continue,
                     }
                 }
-                println!("Pubsub connection dropped");
-            })
-            .await?;
+            }
         }
-        
-        Ok(())
     }
 
     async fn handle_msg(
@@ -149,53 +394,172 @@ impl PubSubHandler {
                                     redeem.data.redemption.reward.title
                                 );
 
-                                if let Some(action) = self.db_conn.get_points_redeem_trigger(
-                                    &redeem.data.redemption.reward.title,
-                                    channel,
-                                )? {
-                                    println!("Executing {}", action);
+                                let redemption = &redeem.data.redemption;
+                                let title = &redemption.reward.title;
+
+                                self.events.publish(BotEvent::RedeemReceived {
+                                    channel: channel.to_string(),
+                                    user: redemption.user.login.clone(),
+                                    reward: title.clone(),
+                                });
 
-                                    let user_input =
-                                        match &redeem.data.redemption.reward.is_user_input_required
+                                //Gate live-only actions on the channel's stream status.
+                                if self.db_conn.get_redeem_requires_live(title, channel)?
+                                    && !self.is_live(channel)
+                                {
+                                    println!("Channel offline, refunding live-only redeem");
+                                    if !redemption.reward.should_redemptions_skip_request_queue {
+                                        if let Err(e) = self
+                                            .twitch_api
+                                            .update_redemption_status(
+                                                id,
+                                                &redemption.reward.id,
+                                                &redemption.id,
+                                                "CANCELED",
+                                            )
+                                            .await
                                         {
-                                            true => redeem.data.redemption.user_input.unwrap(),
+                                            println!(
+                                                "Pubsub: failed to update redemption status: {:?}",
+                                                e
+                                            );
+                                        }
+                                    }
+                                    return Ok(());
+                                }
+
+                                //Honour the reward's configured cooldowns and
+                                //per-stream caps before doing any work, refunding
+                                //redeems that arrive inside an active window. The
+                                //limits are only consumed once the redeem is kept.
+                                if !self.within_limits(
+                                    &redemption.reward,
+                                    channel,
+                                    &redemption.user.id,
+                                ) {
+                                    println!("Redeem on cooldown, refunding");
+                                    if !redemption.reward.should_redemptions_skip_request_queue {
+                                        if let Err(e) = self
+                                            .twitch_api
+                                            .update_redemption_status(
+                                                id,
+                                                &redemption.reward.id,
+                                                &redemption.id,
+                                                "CANCELED",
+                                            )
+                                            .await
+                                        {
+                                            println!(
+                                                "Pubsub: failed to update redemption status: {:?}",
+                                                e
+                                            );
+                                        }
+                                    }
+                                    return Ok(());
+                                }
+
+                                let succeeded = match self
+                                    .db_conn
+                                    .get_points_redeem_trigger(title, channel)?
+                                {
+                                    Some(action) => {
+                                        println!("Executing {}", action);
+
+                                        let user_input = match &redemption.reward.is_user_input_required {
+                                            true => redemption.user_input.clone().unwrap_or_default(),
                                             false => String::new(),
                                         };
-                                    let args: Vec<&str> = user_input.split_whitespace().collect();
-
-                                    let response = self
-                                        .command_handler
-                                        .run_command(
-                                            &Command {
-                                                trigger: String::new(),
-                                                action: Action::Custom(action),
-                                                channel: channel.clone(),
-                                                permissions:
-                                                    crate::command_handler::Permissions::All,
-                                            },
-                                            &args,
-                                            channel,
-                                            client.clone(),
-                                        )
-                                        .await
-                                        .unwrap();
+                                        let args: Vec<&str> =
+                                            user_input.split_whitespace().collect();
 
-                                    match response {
-                                        Some(response) => {
-                                            println!("Action executed, responding: {}", &response);
-                                            client.say(channel.to_owned(), response).await?;
+                                        match self
+                                            .command_handler
+                                            .run_command(
+                                                &Command {
+                                                    trigger: String::new(),
+                                                    action: Action::Custom(action),
+                                                    channel: channel.clone(),
+                                                    permissions:
+                                                        crate::command_handler::Permissions::All,
+                                                },
+                                                &args,
+                                                channel,
+                                                client.clone(),
+                                            )
+                                            .await
+                                        {
+                                            Ok(Some(response)) => {
+                                                println!("Action executed, responding: {}", &response);
+                                                self.events.publish(BotEvent::ActionExecuted {
+                                                    channel: channel.to_string(),
+                                                    reward: title.clone(),
+                                                    response: Some(response.clone()),
+                                                });
+                                                client.say(channel.to_owned(), response).await?;
+                                                true
+                                            }
+                                            Ok(None) => {
+                                                println!("Action executed, no output");
+                                                self.events.publish(BotEvent::ActionExecuted {
+                                                    channel: channel.to_string(),
+                                                    reward: title.clone(),
+                                                    response: None,
+                                                });
+                                                true
+                                            }
+                                            Err(e) => {
+                                                println!("Pubsub: action failed: {:?}", e);
+                                                false
+                                            }
                                         }
-                                        None => println!("Action executed, no output"),
                                     }
-                                } else {
-                                    println!("No action associated with redeem");
+                                    None => {
+                                        println!("No action associated with redeem");
+                                        false
+                                    }
+                                };
+
+                                let fulfilled = succeeded
+                                    || !self.db_conn.get_redeem_cancel_on_failure(title, channel)?;
+
+                                //Skip-queue rewards are auto-fulfilled and cannot be updated.
+                                if !redemption.reward.should_redemptions_skip_request_queue {
+                                    let status = if fulfilled { "FULFILLED" } else { "CANCELED" };
+
+                                    if let Err(e) = self
+                                        .twitch_api
+                                        .update_redemption_status(
+                                            id,
+                                            &redemption.reward.id,
+                                            &redemption.id,
+                                            status,
+                                        )
+                                        .await
+                                    {
+                                        println!(
+                                            "Pubsub: failed to update redemption status: {:?}",
+                                            e
+                                        );
+                                    }
+                                }
+
+                                //Only a kept redeem counts against the caps and
+                                //arms the cooldowns; a refunded one must not block
+                                //subsequent redeems.
+                                if fulfilled {
+                                    self.record_redemption(
+                                        &redemption.reward,
+                                        channel,
+                                        &redemption.user.id,
+                                    );
                                 }
                             }
                         }
-                        Err(e) => println!(
-                            "Pubsub: failed to parse channel point redeems message {}",
-                            e
-                        ),
+                        Err(e) => {
+                            println!("Pubsub: failed to parse channel point redeems message {}", e);
+                            self.events
+                                .publish(BotEvent::ParseError { message: e.to_string() });
+                        }
                     };
                 }
             }
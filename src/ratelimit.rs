@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use tokio::time::sleep;
+
+//Twitch allows roughly 20 messages per 30 seconds for a regular account and a
+//much higher allowance when the bot holds a moderator or broadcaster badge.
+const NORMAL_CAPACITY: f64 = 20.0;
+const ELEVATED_CAPACITY: f64 = 100.0;
+const WINDOW_SECS: f64 = 30.0;
+const GLOBAL_CAPACITY: f64 = 100.0;
+
+#[derive(Clone, Copy)]
+pub enum Role {
+    Normal,
+    Elevated,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    refill_per_sec: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, window_secs: f64) -> Self {
+        TokenBucket {
+            tokens: capacity,
+            capacity,
+            refill_per_sec: capacity / window_secs,
+            last: Instant::now(),
+        }
+    }
+
+    ///Refills the bucket and returns how long to wait before a token is
+    ///available, or a zero duration when one can be consumed right away.
+    fn wait_time(&mut self) -> Duration {
+        let elapsed = self.last.elapsed().as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last = Instant::now();
+
+        match self.tokens >= 1.0 {
+            true => Duration::ZERO,
+            false => Duration::from_secs_f64((1.0 - self.tokens) / self.refill_per_sec),
+        }
+    }
+
+    fn consume(&mut self) {
+        self.tokens -= 1.0;
+    }
+}
+
+///A per-channel token-bucket limiter with a shared global bucket, used to pace
+///outgoing messages within Twitch's documented limits.
+pub struct RateLimiter {
+    global: TokenBucket,
+    channels: HashMap<String, TokenBucket>,
+    roles: HashMap<String, Role>,
+}
+
+impl RateLimiter {
+    pub fn new(roles: HashMap<String, Role>) -> Self {
+        RateLimiter {
+            global: TokenBucket::new(GLOBAL_CAPACITY, WINDOW_SECS),
+            channels: HashMap::new(),
+            roles,
+        }
+    }
+
+    ///Blocks only as long as needed to stay within both the per-channel and the
+    ///global rate limit, then consumes a token from each.
+    pub async fn acquire(&mut self, channel: &str) {
+        let capacity = match self.roles.get(channel) {
+            Some(Role::Elevated) => ELEVATED_CAPACITY,
+            _ => NORMAL_CAPACITY,
+        };
+
+        loop {
+            let bucket = self
+                .channels
+                .entry(channel.to_owned())
+                .or_insert_with(|| TokenBucket::new(capacity, WINDOW_SECS));
+
+            let wait = bucket.wait_time().max(self.global.wait_time());
+
+            if wait.is_zero() {
+                bucket.consume();
+                self.global.consume();
+                return;
+            }
+
+            sleep(wait).await;
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use reqwest::Client;
+use serde_json::{json, Value};
+
+const HELIX: &str = "https://api.twitch.tv/helix";
+const VALIDATE_URL: &str = "https://id.twitch.tv/oauth2/validate";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct User {
+    pub id: String,
+    pub login: String,
+    #[serde(rename = "display_name")]
+    pub display_name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UsersResponse {
+    pub data: Vec<User>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Stream {
+    #[serde(rename = "user_login")]
+    user_login: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct StreamsResponse {
+    data: Vec<Stream>,
+}
+
+///Cached token material, refreshed in place so a long-lived handle keeps
+///working across token rotations.
+struct TokenState {
+    oauth: String,
+    expires_at: Instant,
+}
+
+#[derive(Clone)]
+pub struct TwitchApi {
+    client: Client,
+    client_id: String,
+    token: Arc<RwLock<TokenState>>,
+}
+
+impl TwitchApi {
+    ///Validates the supplied user token, learning the client id and expiry that
+    ///the Helix calls below need.
+    pub async fn init(oauth: &str) -> anyhow::Result<Self> {
+        let client = Client::new();
+
+        let validation: Value = client
+            .get(VALIDATE_URL)
+            .header("Authorization", format!("OAuth {}", oauth))
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        let client_id = validation["client_id"]
+            .as_str()
+            .ok_or_else(|| anyhow!("token validation did not return a client_id"))?
+            .to_owned();
+        let expires_in = validation["expires_in"].as_u64().unwrap_or(0);
+
+        Ok(Self {
+            client,
+            client_id,
+            token: Arc::new(RwLock::new(TokenState {
+                oauth: oauth.to_owned(),
+                expires_at: Instant::now() + Duration::from_secs(expires_in),
+            })),
+        })
+    }
+
+    pub fn get_oauth(&self) -> String {
+        self.token.read().unwrap().oauth.clone()
+    }
+
+    ///Re-validates the token when it is within `threshold` of expiring and
+    ///updates the cached expiry from the answer. The token itself is not
+    ///renewed: a token that is still valid is kept as is, and one that has
+    ///expired makes this return an error.
+    pub async fn refresh_token_if_expiring(&self, threshold: Duration) -> anyhow::Result<()> {
+        let oauth = {
+            let token = self.token.read().unwrap();
+            if token.expires_at.saturating_duration_since(Instant::now()) > threshold {
+                return Ok(());
+            }
+            token.oauth.clone()
+        };
+
+        println!("TwitchApi: token near expiry, re-validating");
+
+        let validation: Value = self
+            .client
+            .get(VALIDATE_URL)
+            .header("Authorization", format!("OAuth {}", oauth))
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        let expires_in = validation["expires_in"]
+            .as_u64()
+            .ok_or_else(|| anyhow!("token is no longer valid"))?;
+
+        self.token.write().unwrap().expires_at = Instant::now() + Duration::from_secs(expires_in);
+
+        Ok(())
+    }
+
+    fn helix(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.client
+            .request(method, format!("{}/{}", HELIX, path))
+            .header("Client-Id", &self.client_id)
+            .header("Authorization", format!("Bearer {}", self.get_oauth()))
+    }
+
+    pub async fn get_users_by_login(&self, logins: &[String]) -> anyhow::Result<UsersResponse> {
+        let query: Vec<(&str, &String)> = logins.iter().map(|login| ("login", login)).collect();
+
+        Ok(self
+            .helix(reqwest::Method::GET, "users")
+            .query(&query)
+            .send()
+            .await?
+            .json()
+            .await?)
+    }
+
+    pub async fn get_users_by_id(&self, ids: &[String]) -> anyhow::Result<UsersResponse> {
+        let query: Vec<(&str, &String)> = ids.iter().map(|id| ("id", id)).collect();
+
+        Ok(self
+            .helix(reqwest::Method::GET, "users")
+            .query(&query)
+            .send()
+            .await?
+            .json()
+            .await?)
+    }
+
+    ///Returns the logins of the channels that are currently streaming, out of
+    ///the ones requested.
+    pub async fn get_live_channels(&self, channels: &[String]) -> anyhow::Result<Vec<String>> {
+        let query: Vec<(&str, &String)> =
+            channels.iter().map(|channel| ("user_login", channel)).collect();
+
+        let response: StreamsResponse = self
+            .helix(reqwest::Method::GET, "streams")
+            .query(&query)
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        Ok(response.data.into_iter().map(|stream| stream.user_login).collect())
+    }
+
+    ///Updates a channel-point redemption's status (`FULFILLED` or `CANCELED`);
+    ///cancelling also refunds the points.
+    pub async fn update_redemption_status(
+        &self,
+        broadcaster_id: &str,
+        reward_id: &str,
+        redemption_id: &str,
+        status: &str,
+    ) -> anyhow::Result<()> {
+        let response = self
+            .helix(
+                reqwest::Method::PATCH,
+                "channel_points/custom_rewards/redemptions",
+            )
+            .query(&[
+                ("broadcaster_id", broadcaster_id),
+                ("reward_id", reward_id),
+                ("id", redemption_id),
+            ])
+            .json(&json!({ "status": status }))
+            .send()
+            .await?;
+
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(anyhow!(
+                "failed to update redemption: {}",
+                response.status()
+            )),
+        }
+    }
+
+    ///Runs a commercial of `duration` seconds on the given channel.
+    pub async fn run_ad(&self, channel: &str, duration: u8) -> Result<(), reqwest::Error> {
+        let users: UsersResponse = self
+            .helix(reqwest::Method::GET, "users")
+            .query(&[("login", channel)])
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        let broadcaster_id = match users.data.first() {
+            Some(user) => user.id.clone(),
+            None => return Ok(()),
+        };
+
+        self.helix(reqwest::Method::POST, "channels/commercial")
+            .json(&json!({
+                "broadcaster_id": broadcaster_id,
+                "length": duration,
+            }))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+}
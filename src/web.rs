@@ -1,10 +1,17 @@
 use crate::twitch_api::TwitchApi;
 use reqwest::{Client, Url};
-use rocket::{response::Redirect, State};
+use rocket::{
+    response::{
+        stream::{Event, EventStream},
+        Redirect,
+    },
+    State,
+};
 use rocket_dyn_templates::Template;
 use serde_json::Value;
+use tokio::sync::broadcast::error::RecvError;
 
-use crate::{command_handler::Command, db::DBConn};
+use crate::{command_handler::Command, db::DBConn, events::EventBus};
 
 const CLIENT_ID: &'static str = "pl0ptknnjoq305qfrw0slqpl0pux33";
 const CLIENT_SECRET: &'static str = "we2b03bv89c93jf2lw7xiyyp8pq6gi";
@@ -32,6 +39,24 @@ fn get_commands(db_conn: &State<DBConn>, channel: String) -> Template {
     Template::render("commands-page", &CommandsPageContext { channel, commands })
 }
 
+///Streams live redemption activity to the browser dashboard over SSE. Each
+///subscriber gets its own receiver off the shared [`EventBus`].
+#[get("/events")]
+fn events(bus: &State<EventBus>) -> EventStream![] {
+    let mut receiver = bus.subscribe();
+
+    EventStream! {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => yield Event::json(&event),
+                //A slow client that fell behind just resumes from the latest events.
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
 #[get("/auth")]
 fn auth() -> Redirect {
     let url = Url::parse_with_params(
@@ -81,12 +106,16 @@ async fn auth_callback(code: String, scope: String) -> String {
     format!("Hello {}", user.display_name.replace("\"", ""))
 }
 
-pub async fn run(db_conn: DBConn) {
+pub async fn run(db_conn: DBConn, events: EventBus) {
     println!("Initializing web");
 
     rocket::build()
         .manage(db_conn)
-        .mount("/", routes![index, get_commands, auth, auth_callback])
+        .manage(events)
+        .mount(
+            "/",
+            routes![index, get_commands, events, auth, auth_callback],
+        )
         .attach(Template::fairing())
         .launch()
         .await